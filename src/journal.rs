@@ -3,6 +3,11 @@ use std::ffi::{CString, CStr};
 use log::{self, Log, LogRecord, LogLocation, SetLoggerError};
 use std::{self, fmt, ptr, result};
 use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use ffi;
 use super::Result;
 
@@ -14,6 +19,18 @@ pub enum SeekRet {
     ClosestSeek,
 }
 
+/// What changed in the journal since the last `process()` call.
+#[derive(PartialEq, Debug)]
+pub enum JournalWaitResult {
+    /// Nothing changed.
+    Nop,
+    /// New entries were appended.
+    Append,
+    /// The journal files were rotated or otherwise invalidated; readers
+    /// should be prepared to re-seek.
+    Invalidate,
+}
+
 /// Send preformatted fields to systemd.
 ///
 /// This is a relatively low-level operation and probably not suitable unless
@@ -28,13 +45,22 @@ pub fn print(lvl: u32, s: &str) -> c_int {
     send(&[&format!("PRIORITY={}", lvl), &format!("MESSAGE={}", s)])
 }
 
+/// Map a `log::LogLevel` to the syslog severity for the journal's
+/// `PRIORITY=` field. The two enums' discriminants don't line up, so this
+/// can't just be a cast.
+fn level_to_priority(level: log::LogLevel) -> usize {
+    match level {
+        log::LogLevel::Error => 3, // LOG_ERR
+        log::LogLevel::Warn => 4, // LOG_WARNING
+        log::LogLevel::Info => 6, // LOG_INFO
+        log::LogLevel::Debug => 7, // LOG_DEBUG
+        log::LogLevel::Trace => 7, // LOG_DEBUG
+    }
+}
+
 /// Send a `log::LogRecord` to systemd.
 pub fn log_record(record: &LogRecord) {
-    let lvl: usize = unsafe {
-        use std::mem;
-        mem::transmute(record.level())
-    };
-    log(lvl, record.location(), record.args());
+    log(level_to_priority(record.level()), record.location(), record.args());
 }
 
 pub fn log(level: usize, loc: &LogLocation, args: &fmt::Arguments) {
@@ -62,7 +88,96 @@ impl JournalLog {
     }
 }
 
-pub type JournalRecord = BTreeMap<String, String>;
+/// A journal entry's fields, keyed by field name. Values are raw bytes since
+/// fields like `MESSAGE` aren't guaranteed to be valid UTF-8.
+pub type JournalRecord = BTreeMap<String, Vec<u8>>;
+
+/// Lossy-UTF-8 convenience for callers that don't need raw bytes.
+fn lossy_string(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+/// Split a raw `sd_journal_enumerate_data` field (`"FIELD=value"`) into its
+/// name and value. Scans for the separator byte rather than treating the
+/// field as UTF-8 or splitting on `'='` as a char, since values may be
+/// binary and may themselves contain `=`.
+fn parse_field(raw: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    match raw.iter().position(|&c| c == b'=') {
+        Some(eq) => Ok((lossy_string(&raw[..eq]), raw[eq + 1..].to_vec())),
+        None => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "journal field has no '=' separator"))
+        }
+    }
+}
+
+/// A typed, pre-parsed view over a `JournalRecord`'s well-known fields.
+/// Anything not pulled into a typed accessor is left in `fields`.
+pub struct Entry {
+    message: Option<String>,
+    hostname: Option<String>,
+    unit: Option<String>,
+    priority: Option<usize>,
+    pid: Option<i32>,
+    realtime: Option<SystemTime>,
+    /// Everything else in the record, with the fields above removed.
+    pub fields: JournalRecord,
+}
+
+impl Entry {
+    /// The `MESSAGE` field.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(String::as_str)
+    }
+
+    /// The `_HOSTNAME` field.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_ref().map(String::as_str)
+    }
+
+    /// The `_SYSTEMD_UNIT` field.
+    pub fn unit(&self) -> Option<&str> {
+        self.unit.as_ref().map(String::as_str)
+    }
+
+    /// The `PRIORITY` field, parsed as a syslog severity (0-7).
+    pub fn priority(&self) -> Option<usize> {
+        self.priority
+    }
+
+    /// The `_PID` field, parsed to a pid.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    /// The `__REALTIME_TIMESTAMP` field, parsed to a `SystemTime`.
+    pub fn realtime(&self) -> Option<SystemTime> {
+        self.realtime
+    }
+}
+
+impl From<JournalRecord> for Entry {
+    fn from(mut record: JournalRecord) -> Entry {
+        let realtime = record.remove("__REALTIME_TIMESTAMP")
+                              .and_then(|t| lossy_string(&t).parse::<u64>().ok())
+                              .map(|us| {
+                                  UNIX_EPOCH +
+                                  Duration::new(us / 1_000_000,
+                                                 ((us % 1_000_000) * 1_000) as u32)
+                              });
+
+        Entry {
+            message: record.remove("MESSAGE").map(|v| lossy_string(&v)),
+            hostname: record.remove("_HOSTNAME").map(|v| lossy_string(&v)),
+            unit: record.remove("_SYSTEMD_UNIT").map(|v| lossy_string(&v)),
+            priority: record.remove("PRIORITY")
+                            .and_then(|p| lossy_string(&p).parse().ok()),
+            pid: record.remove("_PID").and_then(|p| lossy_string(&p).parse().ok()),
+            realtime: realtime,
+            fields: record,
+        }
+    }
+}
 
 /// A cursor into the systemd journal.
 ///
@@ -72,6 +187,50 @@ pub struct Journal {
     wait_time: u64,
 }
 
+/// A 128-bit systemd ID, as used for boot IDs, machine IDs, and similar
+/// (see `sd-id128.h`).
+#[derive(Clone, Copy)]
+pub struct Id128(ffi::sd_id128_t);
+
+impl Id128 {
+    /// The raw 16 bytes of the ID.
+    pub fn as_bytes(&self) -> [u8; 16] {
+        unsafe { std::mem::transmute(self.0) }
+    }
+}
+
+impl fmt::Display for Id128 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.as_bytes() {
+            try!(write!(f, "{:02x}", b));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Id128 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Id128({})", self)
+    }
+}
+
+/// A position to seek a `Journal` to.
+pub enum JournalSeek {
+    /// The start of the journal, i.e. the oldest available entry.
+    Head,
+    /// The end of the journal, i.e. the most recent entry.
+    Tail,
+    /// The entry matching (or closest to, if not found) the given cursor.
+    Cursor(String),
+    /// The entry closest to a monotonic timestamp for a particular boot.
+    ClockMonotonic {
+        boot_id: Id128,
+        usec: u64,
+    },
+    /// The entry closest to a wallclock timestamp, in microseconds since the epoch.
+    ClockRealtime(u64),
+}
+
 /// Represents the set of journal files to read.
 pub enum JournalFiles {
     /// The system-wide journal.
@@ -125,7 +284,21 @@ impl Journal {
     /// Read the next record from the journal. Returns `io::EndOfFile` if there
     /// are no more records to read.
     pub fn next_record(&self) -> Result<Option<JournalRecord>> {
-        if sd_try!(ffi::sd_journal_next(self.j)) == 0 {
+        self.advance_record(|j| unsafe { ffi::sd_journal_next(j) })
+    }
+
+    /// Step to the previous record and read it. Returns `None` if already at
+    /// the head of the journal.
+    pub fn previous_record(&self) -> Result<Option<JournalRecord>> {
+        self.advance_record(|j| unsafe { ffi::sd_journal_previous(j) })
+    }
+
+    /// Shared by `next_record` and `previous_record`, which only differ in
+    /// which direction `advance` steps.
+    fn advance_record<F>(&self, advance: F) -> Result<Option<JournalRecord>>
+        where F: Fn(ffi::sd_journal) -> c_int
+    {
+        if sd_try!(advance(self.j)) == 0 {
             return Ok(None);
         }
         unsafe { ffi::sd_journal_restart_data(self.j) }
@@ -135,14 +308,9 @@ impl Journal {
         let mut sz: size_t = 0;
         let data: *mut u8 = ptr::null_mut();
         while sd_try!(ffi::sd_journal_enumerate_data(self.j, &data, &mut sz)) > 0 {
-            unsafe {
-                let b = ::std::slice::from_raw_parts_mut(data, sz as usize);
-                let field = ::std::str::from_utf8_unchecked(b);
-                let mut name_value = field.splitn(2, '=');
-                let name = name_value.next().unwrap();
-                let value = name_value.next().unwrap();
-                ret.insert(From::from(name), From::from(value));
-            }
+            let b = unsafe { ::std::slice::from_raw_parts(data, sz as usize) };
+            let (name, value) = try!(parse_field(b));
+            ret.insert(name, value);
         }
 
         Ok(Some(ret))
@@ -164,25 +332,42 @@ impl Journal {
         Ok(cursor)
     }
 
-    pub fn seek<S>(&self, cursor: S) -> Result<SeekRet>
-        where S: Into<String>
-    {
-        let c_position = CString::new(cursor.into());
-        // If no entry matching the specified cursor is found the call will seek to
-        // the next closest entry (in terms of time) instead
-        sd_try!(ffi::sd_journal_seek_cursor(self.j,
-                                            c_position.clone().unwrap().as_ptr() as *const _));
+    /// Seek to a position in the journal described by `seek`.
+    ///
+    /// After seeking, call `next_record()` or `previous_record()` to land on
+    /// an entry near the requested position.
+    pub fn seek(&self, seek: JournalSeek) -> Result<SeekRet> {
+        match seek {
+            JournalSeek::Head => {
+                sd_try!(ffi::sd_journal_seek_head(self.j));
+            }
+            JournalSeek::Tail => {
+                sd_try!(ffi::sd_journal_seek_tail(self.j));
+            }
+            JournalSeek::Cursor(cursor) => {
+                // If no entry matching the specified cursor is found the call will seek to
+                // the next closest entry (in terms of time) instead
+                let c_position = CString::new(cursor).unwrap();
+                sd_try!(ffi::sd_journal_seek_cursor(self.j, c_position.as_ptr() as *const _));
+
+                // TODO: Test why sd_journal_test_cursor is failing here
+
+                // match sd_try!(ffi::sd_journal_test_cursor(self.j,
+                //                                           c_position.as_ptr() as *const _)) {
+                //     0 => return Ok(SeekRet::ClosestSeek),
+                //     e if e > 0 => {}
+                //     e => return Err(std::io::Error::from_raw_os_error(-e)),
+                // }
+            }
+            JournalSeek::ClockMonotonic { boot_id, usec } => {
+                sd_try!(ffi::sd_journal_seek_monotonic_usec(self.j, boot_id.0, usec));
+            }
+            JournalSeek::ClockRealtime(usec) => {
+                sd_try!(ffi::sd_journal_seek_realtime_usec(self.j, usec));
+            }
+        }
 
         Ok(SeekRet::SeekSuccess)
-
-        // TODO: Test why sd_journal_test_cursor is failing here
-
-        // match sd_try!(ffi::sd_journal_test_cursor(self.j,
-        //                                           c_position.unwrap().as_ptr() as *const _)) {
-        //     0 => Ok(SeekRet::ClosestSeek),
-        //     e if e > 0 => Ok(SeekRet::SeekSuccess),
-        //     e => Err(std::io::Error::from_raw_os_error(-e)),
-        // }
     }
 
     pub fn get_realtime_us(&self) -> Result<u64> {
@@ -190,6 +375,95 @@ impl Journal {
         sd_try!(ffi::sd_journal_get_realtime_usec(self.j, &mut timestamp_us));
         Ok(timestamp_us)
     }
+
+    /// The monotonic timestamp of the current entry and the boot id it's
+    /// relative to, for use with `JournalSeek::ClockMonotonic`.
+    pub fn get_monotonic_us(&self) -> Result<(u64, Id128)> {
+        let mut timestamp_us: u64 = 0;
+        let mut boot_id: ffi::sd_id128_t = unsafe { std::mem::zeroed() };
+        sd_try!(ffi::sd_journal_get_monotonic_usec(self.j, &mut timestamp_us, &mut boot_id));
+        Ok((timestamp_us, Id128(boot_id)))
+    }
+
+    /// Filter subsequent reads to entries matching `"FIELD=value"`. Matches
+    /// added back to back on different fields are AND'd together, while
+    /// matches on the same field are OR'd; `add_disjunction()` and
+    /// `add_conjunction()` separate groups. Must be called before iterating;
+    /// changing matches requires re-seeking.
+    pub fn add_match<S>(&self, expr: S) -> Result<()>
+        where S: AsRef<[u8]>
+    {
+        let data = expr.as_ref();
+        sd_try!(ffi::sd_journal_add_match(self.j,
+                                          data.as_ptr() as *const _,
+                                          data.len() as size_t));
+        Ok(())
+    }
+
+    /// Start a new AND term: the matches added after this are AND'd, as a
+    /// group, with the matches added before it (at a higher level than
+    /// `add_disjunction()`).
+    pub fn add_conjunction(&self) -> Result<()> {
+        sd_try!(ffi::sd_journal_add_conjunction(self.j));
+        Ok(())
+    }
+
+    /// Start a new OR term: matches added after this are OR'd with matches
+    /// added before it, e.g. to build an OR-group of units.
+    pub fn add_disjunction(&self) -> Result<()> {
+        sd_try!(ffi::sd_journal_add_disjunction(self.j));
+        Ok(())
+    }
+
+    /// Clear all matches added so far.
+    pub fn flush_matches(&self) {
+        unsafe { ffi::sd_journal_flush_matches(self.j) }
+    }
+
+    /// Seek to `stored` if given, otherwise seek to the head of the journal.
+    ///
+    /// Convenience for resuming a `CursorStore`-backed consumer on startup:
+    /// `journal.seek_cursor_or_head(cursor_store.load()?)`.
+    pub fn seek_cursor_or_head(&self, stored: Option<String>) -> Result<SeekRet> {
+        match stored {
+            Some(cursor) => self.seek(JournalSeek::Cursor(cursor)),
+            None => self.seek(JournalSeek::Head),
+        }
+    }
+
+    /// The fd backing this journal, for registering with an external
+    /// `epoll`/`poll` loop instead of blocking in `sd_journal_wait`.
+    pub fn as_raw_fd(&self) -> Result<RawFd> {
+        Ok(sd_try!(ffi::sd_journal_get_fd(self.j)) as RawFd)
+    }
+
+    /// The `poll`-style event mask to watch `as_raw_fd()` for.
+    pub fn get_events(&self) -> Result<c_int> {
+        Ok(sd_try!(ffi::sd_journal_get_events(self.j)))
+    }
+
+    /// The absolute `CLOCK_MONOTONIC` time, in microseconds, by which a
+    /// `poll`/`epoll_wait` on the fd should return; it is not a relative
+    /// timeout. `None` if there's no pending timeout.
+    pub fn get_timeout(&self) -> Result<Option<u64>> {
+        let mut timeout_usec: u64 = 0;
+        sd_try!(ffi::sd_journal_get_timeout(self.j, &mut timeout_usec));
+        if timeout_usec == std::u64::MAX {
+            Ok(None)
+        } else {
+            Ok(Some(timeout_usec))
+        }
+    }
+
+    /// Process pending changes after `as_raw_fd()` becomes readable, before
+    /// draining with `next_record()`/`previous_record()`.
+    pub fn process(&self) -> Result<JournalWaitResult> {
+        match sd_try!(ffi::sd_journal_process(self.j)) {
+            ffi::SD_JOURNAL_APPEND => Ok(JournalWaitResult::Append),
+            ffi::SD_JOURNAL_INVALIDATE => Ok(JournalWaitResult::Invalidate),
+            _ => Ok(JournalWaitResult::Nop),
+        }
+    }
 }
 
 
@@ -202,7 +476,7 @@ impl<'a> Iterator for &'a Journal {
             Err(_) => {
                 error!("error reading a journal entry. adding dummy entry");
                 let mut dummy_tree = BTreeMap::new();
-                dummy_tree.insert("Dummy".to_string(), "Dummy".to_string());
+                dummy_tree.insert("Dummy".to_string(), b"Dummy".to_vec());
                 Some(dummy_tree)
             }
         };
@@ -243,3 +517,132 @@ impl Drop for Journal {
         }
     }
 }
+
+/// Persists the last processed journal cursor to a file, so a long-running
+/// consumer can resume from it on restart instead of re-reading from head.
+pub struct CursorStore {
+    path: PathBuf,
+}
+
+impl CursorStore {
+    /// Create a store backed by `path`. Nothing is touched until `load()` or
+    /// `save()` is called.
+    pub fn new<P: Into<PathBuf>>(path: P) -> CursorStore {
+        CursorStore { path: path.into() }
+    }
+
+    /// Read the previously saved cursor. Returns `None` if `path` doesn't
+    /// exist yet.
+    pub fn load(&self) -> Result<Option<String>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut contents = String::new();
+        try!(file.read_to_string(&mut contents));
+        Ok(Some(contents.trim().to_string()))
+    }
+
+    /// Persist `cursor`, replacing whatever was previously saved.
+    ///
+    /// Writes to a temporary file and renames it over the destination, so a
+    /// crash mid-write can't leave `load()` a torn file to trip over.
+    pub fn save(&self, cursor: &str) -> Result<()> {
+        let mut tmp_path = self.path.clone();
+        tmp_path.set_extension("tmp");
+
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            try!(tmp.write_all(cursor.as_bytes()));
+            try!(tmp.sync_all());
+        }
+
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::LogLevel;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn level_to_priority_maps_to_syslog_severities() {
+        assert_eq!(level_to_priority(LogLevel::Error), 3);
+        assert_eq!(level_to_priority(LogLevel::Warn), 4);
+        assert_eq!(level_to_priority(LogLevel::Info), 6);
+        assert_eq!(level_to_priority(LogLevel::Debug), 7);
+        assert_eq!(level_to_priority(LogLevel::Trace), 7);
+    }
+
+    #[test]
+    fn cursor_store_round_trips_through_save_and_load() {
+        let mut path = env::temp_dir();
+        path.push(format!("asystemd-cursor-store-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = CursorStore::new(path.clone());
+        assert_eq!(store.load().unwrap(), None);
+
+        store.save("cursor-a").unwrap();
+        assert_eq!(store.load().unwrap(), Some("cursor-a".to_string()));
+
+        store.save("cursor-b").unwrap();
+        assert_eq!(store.load().unwrap(), Some("cursor-b".to_string()));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn entry_from_journal_record_extracts_typed_fields() {
+        let mut record: JournalRecord = BTreeMap::new();
+        record.insert("MESSAGE".to_string(), b"hello".to_vec());
+        record.insert("_HOSTNAME".to_string(), b"host1".to_vec());
+        record.insert("_SYSTEMD_UNIT".to_string(), b"nginx.service".to_vec());
+        record.insert("PRIORITY".to_string(), b"3".to_vec());
+        record.insert("_PID".to_string(), b"1234".to_vec());
+        record.insert("__REALTIME_TIMESTAMP".to_string(), b"1000000".to_vec());
+        record.insert("_TRANSPORT".to_string(), b"stdout".to_vec());
+
+        let entry = Entry::from(record);
+
+        assert_eq!(entry.message(), Some("hello"));
+        assert_eq!(entry.hostname(), Some("host1"));
+        assert_eq!(entry.unit(), Some("nginx.service"));
+        assert_eq!(entry.priority(), Some(3));
+        assert_eq!(entry.pid(), Some(1234));
+        assert_eq!(entry.realtime(), Some(UNIX_EPOCH + Duration::new(1, 0)));
+        assert_eq!(entry.fields.get("_TRANSPORT").map(Vec::as_slice), Some(&b"stdout"[..]));
+        assert!(!entry.fields.contains_key("MESSAGE"));
+    }
+
+    #[test]
+    fn parse_field_splits_name_and_value() {
+        let (name, value) = parse_field(b"FIELD=value").unwrap();
+        assert_eq!(name, "FIELD");
+        assert_eq!(value, b"value".to_vec());
+    }
+
+    #[test]
+    fn parse_field_keeps_non_utf8_values_as_raw_bytes() {
+        let (name, value) = parse_field(b"MESSAGE=\xff\xfe").unwrap();
+        assert_eq!(name, "MESSAGE");
+        assert_eq!(value, vec![0xff, 0xfe]);
+    }
+
+    #[test]
+    fn parse_field_only_splits_on_the_first_equals() {
+        let (name, value) = parse_field(b"FIELD=a=b=c").unwrap();
+        assert_eq!(name, "FIELD");
+        assert_eq!(value, b"a=b=c".to_vec());
+    }
+
+    #[test]
+    fn parse_field_rejects_missing_separator() {
+        let err = parse_field(b"NOEQUALSIGN").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
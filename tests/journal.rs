@@ -2,7 +2,7 @@
 extern crate systemd;
 #[macro_use]
 extern crate log;
-use systemd::journal::{self, Journal, JournalFiles, SeekRet};
+use systemd::journal::{self, Journal, JournalFiles, JournalSeek, SeekRet};
 
 // #[test]
 fn test() {
@@ -29,7 +29,7 @@ fn seek_test() {
         }
     };
 
-    match client.seek(cursor.clone()) {
+    match client.seek(JournalSeek::Cursor(cursor.clone())) {
         Ok(r) => {
             if r == SeekRet::ClosestSeek {
                 println!("Invalid cursor. Seeking to closest\n. cursor = {}", cursor);